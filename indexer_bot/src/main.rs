@@ -1,55 +1,578 @@
+use base64::Engine as _;
 use log::{error, info, warn};
 use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
+use serde::de;
+use serde::{Deserialize, Deserializer};
 use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::error::Error;
-use tokio::sync::mpsc::{self, Sender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::Error as WsError;
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use dotenv::dotenv;
 
+/// A `programNotification` pushed by the server, mirroring Solana's pubsub
+/// shape. Decoded from the raw frame; account `data` is turned into bytes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProgramNotification {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: NotificationParams,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotificationParams {
+    pub result: ProgramResult,
+    pub subscription: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProgramResult {
+    pub context: RpcResponseContext,
+    pub value: ProgramValue,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RpcResponseContext {
+    pub slot: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProgramValue {
+    pub pubkey: String,
+    pub account: AccountInfo,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountInfo {
+    pub lamports: u64,
+    pub data: AccountData,
+    pub owner: String,
+    pub executable: bool,
+    pub rent_epoch: u64,
+    #[serde(default)]
+    pub space: Option<u64>,
+}
+
+/// Account `data`, carried over the wire as `[<payload>, <encoding>]` and
+/// decoded into raw bytes according to the declared encoding.
+#[derive(Debug, Clone)]
+pub struct AccountData {
+    pub bytes: Vec<u8>,
+    pub encoding: String,
+}
+
+impl<'de> Deserialize<'de> for AccountData {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (payload, encoding): (String, String) = Deserialize::deserialize(deserializer)?;
+        let bytes = match encoding.as_str() {
+            "base64" => base64::engine::general_purpose::STANDARD
+                .decode(payload.as_bytes())
+                .map_err(de::Error::custom)?,
+            "base58" => bs58::decode(payload.as_bytes())
+                .into_vec()
+                .map_err(de::Error::custom)?,
+            other => return Err(de::Error::custom(format!("unsupported account encoding {}", other))),
+        };
+        Ok(AccountData { bytes, encoding })
+    }
+}
+
+/// A single registered subscription and its bookkeeping.
+#[derive(Clone)]
+struct SubscriptionState {
+    client_sub_id: u64,
+    method: String,
+    params: Value,
+    // Server-assigned subscription number, filled in from the confirmation
+    // frame. Cleared at the start of each resubscribe pass after a (re)connect
+    // and repopulated from the new confirmations.
+    server_sub_id: Option<u64>,
+    consumer: Sender<Value>,
+}
+
+/// Runtime commands for the [`SubscriptionManager`], carried over the control
+/// channel so callers can add or drop subscriptions while the socket is live.
+pub enum SubscriptionCommand {
+    Subscribe {
+        method: String,
+        params: Value,
+        consumer: Sender<Value>,
+        ack: Option<oneshot::Sender<u64>>,
+    },
+    Unsubscribe {
+        client_sub_id: u64,
+    },
+    /// Unsubscribe everything, perform the WebSocket close handshake and exit
+    /// the reconnect loop cleanly.
+    Shutdown,
+}
+
+/// Tracks every active subscription so they can be demultiplexed by the
+/// server-assigned id and replayed verbatim after a reconnect.
+#[derive(Clone)]
+pub struct SubscriptionManager {
+    next_id: Arc<AtomicU64>,
+    // client_sub_id -> state
+    subscriptions: Arc<Mutex<HashMap<u64, SubscriptionState>>>,
+    // server_sub_id -> client_sub_id, for routing incoming notifications
+    by_server: Arc<Mutex<HashMap<u64, u64>>>,
+    // outgoing request id -> client_sub_id, for matching confirmation frames
+    pending_subscribe: Arc<Mutex<HashMap<u64, u64>>>,
+    // client_sub_id -> ack for a Subscribe command, fired once the server
+    // confirms the subscription. Dropping it without firing (e.g. because the
+    // subscription was removed) resolves the caller's `rx.await` with a
+    // `RecvError`, surfacing the rejection instead of a false-positive ack.
+    acks: Arc<Mutex<HashMap<u64, oneshot::Sender<u64>>>>,
+}
+
+impl SubscriptionManager {
+    fn new() -> Self {
+        SubscriptionManager {
+            next_id: Arc::new(AtomicU64::new(1)),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            by_server: Arc::new(Mutex::new(HashMap::new())),
+            pending_subscribe: Arc::new(Mutex::new(HashMap::new())),
+            acks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Register a new subscription and return its client-side id. The actual
+    /// subscribe frame is sent by the connector once a socket is available.
+    /// `ack`, if given, fires with the client-side id once the server
+    /// confirms the subscription (see [`SubscriptionManager::confirm`]).
+    async fn register(
+        &self,
+        method: String,
+        params: Value,
+        consumer: Sender<Value>,
+        ack: Option<oneshot::Sender<u64>>,
+    ) -> u64 {
+        let client_sub_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let state = SubscriptionState {
+            client_sub_id,
+            method,
+            params,
+            server_sub_id: None,
+            consumer,
+        };
+        self.subscriptions.lock().await.insert(client_sub_id, state);
+        if let Some(ack) = ack {
+            self.acks.lock().await.insert(client_sub_id, ack);
+        }
+        client_sub_id
+    }
+
+    async fn remove(&self, client_sub_id: u64) -> Option<SubscriptionState> {
+        let state = self.subscriptions.lock().await.remove(&client_sub_id);
+        if let Some(ref state) = state {
+            if let Some(server) = state.server_sub_id {
+                self.by_server.lock().await.remove(&server);
+            }
+        }
+        self.acks.lock().await.remove(&client_sub_id);
+        state
+    }
+
+    async fn snapshot(&self) -> Vec<SubscriptionState> {
+        self.subscriptions.lock().await.values().cloned().collect()
+    }
+
+    async fn note_pending(&self, request_id: u64, client_sub_id: u64) {
+        self.pending_subscribe.lock().await.insert(request_id, client_sub_id);
+    }
+
+    /// Resolve a numeric reply against outstanding subscribe requests. Returns
+    /// `true` when the request id matched an outstanding subscribe request; the
+    /// server id is recorded only if that subscription is still registered.
+    async fn confirm(&self, request_id: u64, server_sub_id: u64) -> bool {
+        let client_sub_id = match self.pending_subscribe.lock().await.remove(&request_id) {
+            Some(id) => id,
+            None => return false,
+        };
+        if let Some(state) = self.subscriptions.lock().await.get_mut(&client_sub_id) {
+            state.server_sub_id = Some(server_sub_id);
+            self.by_server.lock().await.insert(server_sub_id, client_sub_id);
+            info!(
+                "Subscription {} confirmed as server id {}",
+                client_sub_id, server_sub_id
+            );
+        }
+        if let Some(ack) = self.acks.lock().await.remove(&client_sub_id) {
+            let _ = ack.send(client_sub_id);
+        }
+        true
+    }
+
+    /// Resolve an `error` reply against outstanding subscribe requests,
+    /// dropping the dead subscription (and failing its ack, if any) instead of
+    /// leaving it registered with no `server_sub_id` to be replayed by every
+    /// future `resubscribe_all()`. Returns the removed state so the caller can
+    /// log the rejection.
+    async fn fail_pending(&self, request_id: u64) -> Option<SubscriptionState> {
+        let client_sub_id = self.pending_subscribe.lock().await.remove(&request_id)?;
+        self.remove(client_sub_id).await
+    }
+
+    /// Find the consumer channel for a notification's `params.subscription`.
+    async fn consumer_for(&self, server_sub_id: u64) -> Option<Sender<Value>> {
+        let client_sub_id = *self.by_server.lock().await.get(&server_sub_id)?;
+        self.subscriptions
+            .lock()
+            .await
+            .get(&client_sub_id)
+            .map(|s| s.consumer.clone())
+    }
+
+    /// Invalidate every server-assigned id ahead of a resubscribe pass.
+    async fn reset_server_ids(&self) {
+        self.by_server.lock().await.clear();
+        self.pending_subscribe.lock().await.clear();
+        for state in self.subscriptions.lock().await.values_mut() {
+            state.server_sub_id = None;
+        }
+    }
+}
+
 pub struct SolanaConnector {
     uri: String,
     max_reconnect_attempts: u32,
+    // Extra HTTP headers (API keys, bearer tokens) added to the upgrade request
+    // so hosted providers like Helius, QuickNode or Triton can authenticate.
+    headers: HashMap<String, String>,
+    // Monotonic counter handing out the `id` field of every outgoing request.
+    request_id: Arc<AtomicU64>,
+    // Requests we've sent and are still waiting on a reply for, keyed by id.
+    pending: Arc<Mutex<BTreeMap<u64, oneshot::Sender<Value>>>>,
+    // Sink into the current connection's writer task. `None` while disconnected.
+    outgoing: Arc<Mutex<Option<mpsc::UnboundedSender<Message>>>>,
+    subs: SubscriptionManager,
+    // Fan-out sinks: every notification is pushed to each registered consumer
+    // (DB writer, metrics collector, rebroadcaster) off one upstream socket.
+    subscribers: Arc<RwLock<Vec<UnboundedSender<Value>>>>,
+    // Reconnect backoff: delay starts at `base_delay`, doubles each attempt up
+    // to `max_delay`, with random jitter added on top.
+    base_delay: Duration,
+    max_delay: Duration,
+    // How often to send an application-level Ping; if no frame arrives within
+    // twice this window the socket is treated as dead and reconnected.
+    ping_interval: Duration,
 }
 
 impl SolanaConnector {
-    pub fn new(uri: &str, max_reconnect_attempts: u32) -> Self {
+    pub fn new(uri: &str, max_reconnect_attempts: u32, headers: HashMap<String, String>) -> Self {
         SolanaConnector {
             uri: uri.to_string(),
             max_reconnect_attempts,
+            headers,
+            request_id: Arc::new(AtomicU64::new(1)),
+            pending: Arc::new(Mutex::new(BTreeMap::new())),
+            outgoing: Arc::new(Mutex::new(None)),
+            subs: SubscriptionManager::new(),
+            subscribers: Arc::new(RwLock::new(Vec::new())),
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            ping_interval: Duration::from_secs(30),
         }
     }
 
-    fn prepare_subscribe_msg(&self, program_id: &str) -> Value {
-        json!({
+    /// Override the reconnect backoff bounds.
+    pub fn with_backoff(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Override the liveness ping interval.
+    pub fn with_ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    /// Backoff delay for the given attempt: `base * 2^(attempt-1)` capped at
+    /// `max_delay`, plus up to one base-delay of random jitter.
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let scaled = 1u32
+            .checked_shl(exp)
+            .and_then(|mult| self.base_delay.checked_mul(mult))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let jitter_ms = rand::random::<u64>() % (self.base_delay.as_millis() as u64 + 1);
+        scaled + Duration::from_millis(jitter_ms)
+    }
+
+    /// Register an independent consumer of the notification stream. Each call
+    /// returns its own receiver; every live consumer sees every notification.
+    pub async fn register(&self) -> UnboundedReceiver<Value> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.write().await.push(tx);
+        rx
+    }
+
+    /// Push a notification to every registered consumer, pruning any whose
+    /// receiver has been dropped.
+    async fn broadcast(&self, notification: &Value) {
+        let mut subscribers = self.subscribers.write().await;
+        subscribers.retain(|tx| tx.send(notification.clone()).is_ok());
+    }
+
+    /// Handle to the subscription registry, used to pre-register subscriptions
+    /// before connecting.
+    pub fn subscriptions(&self) -> &SubscriptionManager {
+        &self.subs
+    }
+
+    /// Build the WebSocket upgrade request, layering any configured headers on
+    /// top of the ones tungstenite derives from the URI.
+    fn build_request(&self) -> Result<Request, Box<dyn Error + Send + Sync>> {
+        let mut request = self.uri.as_str().into_client_request()?;
+        let target = request.headers_mut();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())?;
+            let value = HeaderValue::from_str(value)?;
+            target.insert(name, value);
+        }
+        Ok(request)
+    }
+
+    /// Issue an arbitrary JSON-RPC call over the live socket and await its reply.
+    ///
+    /// Allocates the next request id, parks a oneshot in the pending map and
+    /// serializes `{"jsonrpc":"2.0","id":<n>,"method":...,"params":...}` onto the
+    /// writer task. The read loop completes the oneshot once the matching reply
+    /// (or subscription-confirmation) frame arrives.
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, Box<dyn Error + Send + Sync>> {
+        let id = self.request_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(id, tx);
+
+        let request = json!({
             "jsonrpc": "2.0",
-            "id": "1",
-            "method": "programSubscribe",
-            "params": [
-                program_id,
-                {
-                    "encoding": "base64",
-                    "commitment": "confirmed"
-                }
-            ]
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        if let Err(e) = self.send(Message::Text(request.to_string())).await {
+            // Writer task is gone; don't leak the pending entry.
+            self.pending.lock().await.remove(&id);
+            return Err(e);
+        }
+
+        let result = rx.await?;
+        Ok(result)
+    }
+
+    /// Push a frame onto the current connection's writer task.
+    async fn send(&self, message: Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let guard = self.outgoing.lock().await;
+        let sink = guard.as_ref().ok_or_else(|| {
+            Box::new(std::io::Error::new(std::io::ErrorKind::NotConnected, "not connected"))
+                as Box<dyn Error + Send + Sync>
+        })?;
+        sink.send(message).map_err(|_| {
+            Box::new(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "writer task closed"))
+                as Box<dyn Error + Send + Sync>
         })
     }
 
-    pub async fn connect(&self, program_id: &str, sender: Sender<Value>) -> Result<(), Box<dyn Error>> {
+    /// Emit the subscribe frame for one registered subscription, remembering the
+    /// request id so the confirmation can be matched back.
+    async fn send_subscribe(&self, state: &SubscriptionState) {
+        let id = self.request_id.fetch_add(1, Ordering::SeqCst);
+        self.subs.note_pending(id, state.client_sub_id).await;
+        let frame = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": state.method,
+            "params": state.params,
+        });
+        if self.send(Message::Text(frame.to_string())).await.is_err() {
+            warn!("Failed to send subscribe frame for {}", state.client_sub_id);
+        }
+    }
+
+    /// Replay every registered subscription after a (re)connect so no stream is
+    /// lost across a drop.
+    async fn resubscribe_all(&self) {
+        self.subs.reset_server_ids().await;
+        let subs = self.subs.snapshot().await;
+        info!("Replaying {} subscription(s)", subs.len());
+        for state in subs {
+            self.send_subscribe(&state).await;
+        }
+    }
+
+    async fn handle_command(&self, command: SubscriptionCommand) {
+        match command {
+            SubscriptionCommand::Subscribe { method, params, consumer, ack } => {
+                // `ack` fires once the server confirms the subscription (see
+                // `SubscriptionManager::confirm`), not here, so callers don't
+                // get a false-positive ack for a subscribe the server is
+                // about to reject.
+                let client_sub_id = self
+                    .subs
+                    .register(method, params, consumer, ack)
+                    .await;
+                if let Some(state) = self.subs.subscriptions.lock().await.get(&client_sub_id).cloned() {
+                    self.send_subscribe(&state).await;
+                }
+            }
+            SubscriptionCommand::Unsubscribe { client_sub_id } => {
+                if let Some(state) = self.subs.remove(client_sub_id).await {
+                    if let Some(server) = state.server_sub_id {
+                        // Best-effort, fire-and-forget: awaiting the reply here
+                        // would stall the select loop that dispatches it.
+                        if self.send_unsubscribe(&state.method, server).await.is_err() {
+                            warn!("Failed to send unsubscribe for {}", client_sub_id);
+                        }
+                    }
+                }
+            }
+            // Intercepted by the select loop in `connect`; handled there.
+            SubscriptionCommand::Shutdown => {}
+        }
+    }
+
+    /// Best-effort unsubscribe of every active subscription ahead of shutdown.
+    async fn unsubscribe_all(&self) {
+        for state in self.subs.snapshot().await {
+            if let Some(server) = state.server_sub_id {
+                let _ = self.send_unsubscribe(&state.method, server).await;
+            }
+        }
+    }
+
+    /// Fire a best-effort unsubscribe frame for a server subscription id. We
+    /// don't await the reply: the read loop that would dispatch it runs in the
+    /// same task as our callers.
+    async fn send_unsubscribe(&self, method: &str, server_sub_id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let unsub = method.replace("Subscribe", "Unsubscribe");
+        let id = self.request_id.fetch_add(1, Ordering::SeqCst);
+        let frame = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": unsub,
+            "params": [server_sub_id],
+        });
+        self.send(Message::Text(frame.to_string())).await
+    }
+
+    /// Route a text frame: numeric-id frames complete a pending `call` or a
+    /// subscribe confirmation; frames carrying a `method` are notifications and
+    /// are demultiplexed to the subscription's consumer.
+    async fn dispatch_frame(
+        &self,
+        data: Value,
+        sender: &Sender<Value>,
+        typed: &Sender<ProgramNotification>,
+    ) {
+        if let Some(id) = data["id"].as_u64() {
+            if let Some(server_sub_id) = data["result"].as_u64() {
+                if self.subs.confirm(id, server_sub_id).await {
+                    return;
+                }
+            }
+            if data["error"].is_object() {
+                if let Some(state) = self.subs.fail_pending(id).await {
+                    warn!(
+                        "Subscribe request {} ({}) rejected by server: {}",
+                        id, state.method, data["error"]
+                    );
+                    return;
+                }
+            }
+            if let Some(tx) = self.pending.lock().await.remove(&id) {
+                // Hand the whole frame back so the caller can read `result`
+                // (a plain value, a subscription number, or an `error`).
+                let _ = tx.send(data);
+            } else {
+                warn!("Reply for unknown request id {}", id);
+            }
+        } else if data["method"].is_string() {
+            // Fan the notification out to every independently-registered consumer.
+            self.broadcast(&data).await;
+            // Offer decoded, typed notifications to downstream indexers; fall
+            // back to log-and-skip on anything that doesn't match the schema.
+            if data["method"] == "programNotification" {
+                match serde_json::from_value::<ProgramNotification>(data.clone()) {
+                    Ok(notification) => {
+                        if typed.send(notification).await.is_err() {
+                            warn!("Failed to send typed notification to channel.");
+                        }
+                    }
+                    Err(e) => warn!("Skipping unrecognized programNotification: {}", e),
+                }
+            }
+            let consumer = data["params"]["subscription"]
+                .as_u64()
+                .map(|server| self.subs.consumer_for(server));
+            let target = match consumer {
+                Some(fut) => fut.await.unwrap_or_else(|| sender.clone()),
+                None => sender.clone(),
+            };
+            if target.send(data).await.is_err() {
+                warn!("Failed to send notification to channel.");
+            }
+        } else {
+            // Neither a numeric `id` nor a `method` (e.g. a string-id reply):
+            // not routed anywhere, only logged.
+            info!("Connection success with the Solana WebSocket");
+        }
+    }
+
+    /// Fail every in-flight `call` so callers don't hang across a disconnect.
+    async fn drain_pending(&self) {
+        let mut pending = self.pending.lock().await;
+        if !pending.is_empty() {
+            warn!("Draining {} pending request(s) on disconnect", pending.len());
+        }
+        // Dropping each oneshot sender resolves the caller's `rx.await` with a
+        // `RecvError`, surfacing the disconnect instead of blocking forever.
+        pending.clear();
+    }
+
+    pub async fn connect(
+        &self,
+        sender: Sender<Value>,
+        typed: Sender<ProgramNotification>,
+        mut commands: mpsc::Receiver<SubscriptionCommand>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let mut reconnect_attempts = 0;
 
         loop {
-            let ws_stream = match connect_async(&self.uri).await {
+            let request = self.build_request()?;
+            let ws_stream = match connect_async(request).await {
                 Ok((stream, response)) => {
                     info!("Connected with response: {:?}", response);
+                    // Fresh connection; restart the backoff sequence.
+                    reconnect_attempts = 0;
                     stream
                 }
                 Err(e) => {
                     error!("Failed to connect: {}", e);
                     if reconnect_attempts < self.max_reconnect_attempts {
                         reconnect_attempts += 1;
-                        warn!("Reconnection attempt {} of {}", reconnect_attempts, self.max_reconnect_attempts);
+                        let delay = self.backoff_delay(reconnect_attempts);
+                        warn!(
+                            "Reconnection attempt {} of {} in {:?}",
+                            reconnect_attempts, self.max_reconnect_attempts, delay
+                        );
+                        tokio::time::sleep(delay).await;
                         continue;
                     } else {
                         return Err(Box::new(e));
@@ -59,67 +582,189 @@ impl SolanaConnector {
 
             let (mut write, mut read) = ws_stream.split();
 
-            let subscribe_msg = self.prepare_subscribe_msg(program_id);
-            if write.send(Message::Text(subscribe_msg.to_string())).await.is_err() {
-                error!("Failed to send subscription message.");
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed to send message")));
-            }
-            info!("Subscription message sent.");
-
-            while let Some(msg) = read.next().await {
-                match msg {
-                    Ok(message) => match message {
-                        Message::Text(text) => {
-                            if let Ok(data) = serde_json::from_str::<Value>(&text) {
-                                if data["id"].is_string() && data["jsonrpc"].is_string() && data["result"].is_number() {
-                                    info!("Connection success with the Solana WebSocket");
-                                } else  {
-                                    if sender.send(data).await.is_err() {
-                                        warn!("Failed to send message to channel.");
+            // Spin up a writer task fed by an unbounded channel so `call`, the
+            // subscription replay and the read loop's pong replies can all push
+            // frames from anywhere.
+            let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Message>();
+            *self.outgoing.lock().await = Some(out_tx.clone());
+            let writer = tokio::spawn(async move {
+                while let Some(msg) = out_rx.recv().await {
+                    let is_close = matches!(msg, Message::Close(_));
+                    if write.send(msg).await.is_err() {
+                        warn!("Writer task failed to send frame.");
+                        break;
+                    }
+                    if is_close {
+                        // Flush the close handshake and stop writing.
+                        let _ = write.close().await;
+                        break;
+                    }
+                }
+            });
+
+            // Liveness watchdog: ping on an interval and, if no frame arrives
+            // within the timeout window, flag the socket as dead so a silently
+            // wedged connection still triggers a reconnect.
+            let last_activity = Arc::new(Mutex::new(Instant::now()));
+            let (dead_tx, mut dead_rx) = mpsc::channel::<()>(1);
+            let liveness = {
+                let ping_out = out_tx.clone();
+                let activity = last_activity.clone();
+                let interval = self.ping_interval;
+                let timeout = self.ping_interval * 2;
+                tokio::spawn(async move {
+                    let mut ticker = tokio::time::interval(interval);
+                    ticker.tick().await; // consume the immediate first tick
+                    loop {
+                        ticker.tick().await;
+                        if ping_out.send(Message::Ping(Vec::new())).is_err() {
+                            break;
+                        }
+                        let idle = activity.lock().await.elapsed();
+                        if idle > timeout {
+                            warn!("No activity for {:?}; treating connection as dead.", idle);
+                            let _ = dead_tx.send(()).await;
+                            break;
+                        }
+                    }
+                })
+            };
+
+            self.resubscribe_all().await;
+
+            loop {
+                tokio::select! {
+                    _ = dead_rx.recv() => {
+                        warn!("Liveness check failed; reconnecting.");
+                        break;
+                    }
+                    msg = read.next() => {
+                        let msg = match msg {
+                            Some(msg) => msg,
+                            None => break,
+                        };
+                        *last_activity.lock().await = Instant::now();
+                        match msg {
+                            Ok(message) => match message {
+                                Message::Text(text) => {
+                                    if let Ok(data) = serde_json::from_str::<Value>(&text) {
+                                        self.dispatch_frame(data, &sender, &typed).await;
+                                    } else {
+                                        warn!("Failed to deserialize message.");
+                                    }
+                                }
+                                Message::Ping(ping_data) => {
+                                    if self.send(Message::Pong(ping_data)).await.is_err() {
+                                        warn!("Failed to send pong.");
                                     } else {
-                                        info!("Message sent to channel.");
+                                        info!("Sending pong in response to ping.");
+                                    }
+                                }
+                                Message::Pong(_) => {
+                                    info!("Received pong.");
+                                }
+                                _ => {
+                                    info!("Received an unhandled message type.");
+                                }
+                            },
+                            Err(e) => {
+                                // Any read error means this socket is gone; route
+                                // every variant into the reconnect path rather than
+                                // unwinding `connect` on all but one string.
+                                match &e {
+                                    WsError::ConnectionClosed | WsError::AlreadyClosed => {
+                                        info!("Connection closed; reconnecting.");
+                                    }
+                                    WsError::Protocol(_) | WsError::Io(_) => {
+                                        error!("Read error: {}; reconnecting.", e);
+                                    }
+                                    other => {
+                                        error!("Read error: {}; reconnecting.", other);
                                     }
                                 }
-                            } else {
-                                warn!("Failed to deserialize message.");
+                                break;
                             }
                         }
-                        Message::Ping(ping_data) => {
-                            if write.send(Message::Pong(ping_data)).await.is_err() {
-                                warn!("Failed to send pong.");
-                            } else {
-                                info!("Sending pong in response to ping.");
+                    }
+                    Some(command) = commands.recv() => {
+                        if let SubscriptionCommand::Shutdown = command {
+                            info!("Shutdown requested; closing connection.");
+                            self.unsubscribe_all().await;
+                            let _ = self.send(Message::Close(None)).await;
+                            // Drain the peer's reply until it acknowledges the
+                            // close, but bound the wait so a wedged or partitioned
+                            // socket can't block shutdown forever.
+                            let drain = async {
+                                while let Some(msg) = read.next().await {
+                                    match msg {
+                                        Ok(Message::Close(_)) | Err(_) => break,
+                                        Ok(_) => continue,
+                                    }
+                                }
+                            };
+                            if tokio::time::timeout(self.ping_interval, drain).await.is_err() {
+                                warn!("Timed out waiting for peer close acknowledgement.");
                             }
+                            self.teardown_connection(&writer, &liveness).await;
+                            return Ok(());
                         }
-                        Message::Pong(_) => {
-                            info!("Received pong.");
-                        }
-                        _ => {
-                            info!("Received an unhandled message type.");
-                        }
-                    },
-                    Err(e) => {
-                        error!("Error reading message: {}", e);
-                        if e.to_string() == "WebSocket protocol error: Connection reset without closing handshake" {
-                            break;
-                        } else {
-                            return Err(Box::new(e));
-                        }
+                        self.handle_command(command).await;
                     }
                 }
             }
+
+            self.teardown_connection(&writer, &liveness).await;
+
             if reconnect_attempts < self.max_reconnect_attempts {
                 reconnect_attempts += 1;
-                warn!("Reconnection attempt {} of {}", reconnect_attempts, self.max_reconnect_attempts);
+                let delay = self.backoff_delay(reconnect_attempts);
+                warn!(
+                    "Reconnection attempt {} of {} in {:?}",
+                    reconnect_attempts, self.max_reconnect_attempts, delay
+                );
+                tokio::time::sleep(delay).await;
             } else {
                 return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Max reconnection attempts reached")));
             }
         }
     }
+
+    /// Tear down per-connection state: stop the writer and liveness tasks, clear
+    /// the sink and fail any outstanding requests.
+    async fn teardown_connection(
+        &self,
+        writer: &tokio::task::JoinHandle<()>,
+        liveness: &tokio::task::JoinHandle<()>,
+    ) {
+        *self.outgoing.lock().await = None;
+        writer.abort();
+        liveness.abort();
+        self.drain_pending().await;
+    }
+}
+
+/// Parse `WS_HEADERS` (comma-separated `Name: Value` pairs) into a header map,
+/// e.g. `x-api-key: abc, Authorization: Bearer xyz`.
+fn parse_ws_headers(raw: &str) -> HashMap<String, String> {
+    raw.split(',')
+        .filter_map(|pair| pair.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn program_subscribe_params(program_id: &str) -> Value {
+    json!([
+        program_id,
+        {
+            "encoding": "base64",
+            "commitment": "confirmed"
+        }
+    ])
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     dotenv().ok();
     env_logger::init();
 
@@ -130,18 +775,322 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .parse()
         .expect("MAX_RECONNECT_ATTEMPTS must be a valid u32");
 
-    let (tx, mut rx) = mpsc::channel(100);
+    let headers = env::var("WS_HEADERS")
+        .map(|raw| parse_ws_headers(&raw))
+        .unwrap_or_default();
+
+    let (tx, mut rx) = mpsc::channel::<Value>(100);
+    let (typed_tx, mut typed_rx) = mpsc::channel::<ProgramNotification>(100);
+    let (command_tx, command_rx) = mpsc::channel(32);
+
+    let connector = SolanaConnector::new(&ws_endpoint, max_reconnect_attempts, headers);
+    // Pre-register the program subscription so it is replayed on every connect.
+    connector
+        .subscriptions()
+        .register("programSubscribe".to_string(), program_subscribe_params(&program_id), tx.clone(), None)
+        .await;
 
-    let connector = SolanaConnector::new(&ws_endpoint, max_reconnect_attempts);
+    // An independent consumer fed by the fan-out broadcaster.
+    let mut metrics_rx = connector.register().await;
     tokio::spawn(async move {
-        if let Err(e) = connector.connect(&program_id, tx).await {
+        let mut count: u64 = 0;
+        while metrics_rx.recv().await.is_some() {
+            count += 1;
+            info!("notifications seen: {}", count);
+        }
+    });
+
+    tokio::spawn(async move {
+        if let Err(e) = connector.connect(tx, typed_tx, command_rx).await {
             error!("Failed to connect: {}", e);
         }
     });
 
-    while let Some(message) = rx.recv().await {
-        println!("Received message: {:?}", message);
+    // Trigger a graceful shutdown on Ctrl-C.
+    let shutdown_tx = command_tx.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = shutdown_tx.send(SubscriptionCommand::Shutdown).await;
+        }
+    });
+
+    // Keep the command channel open for runtime subscription changes.
+    let _command_tx = command_tx;
+
+    // Drain the raw channel so it doesn't back-pressure the read loop.
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+
+    while let Some(notification) = typed_rx.recv().await {
+        let slot = notification.params.result.context.slot;
+        let account = &notification.params.result.value.account;
+        println!(
+            "slot {} pubkey {} {} bytes",
+            slot,
+            notification.params.result.value.pubkey,
+            account.data.bytes.len()
+        );
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_data_decodes_base64() {
+        let data: AccountData = serde_json::from_value(json!(["AQID", "base64"])).unwrap();
+        assert_eq!(data.bytes, vec![1, 2, 3]);
+        assert_eq!(data.encoding, "base64");
+    }
+
+    #[test]
+    fn account_data_decodes_base58() {
+        let encoded = bs58::encode(&[1u8, 2, 3]).into_string();
+        let data: AccountData = serde_json::from_value(json!([encoded, "base58"])).unwrap();
+        assert_eq!(data.bytes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn account_data_rejects_unsupported_encoding() {
+        // base64+zstd would need decompression; until then it's unsupported.
+        assert!(serde_json::from_value::<AccountData>(json!(["AQID", "base64+zstd"])).is_err());
+        assert!(serde_json::from_value::<AccountData>(json!(["{}", "jsonParsed"])).is_err());
+    }
+
+    #[test]
+    fn parse_ws_headers_collects_multiple_pairs() {
+        let headers = parse_ws_headers("x-api-key: abc, Authorization: Bearer xyz");
+        assert_eq!(headers.get("x-api-key"), Some(&"abc".to_string()));
+        assert_eq!(headers.get("Authorization"), Some(&"Bearer xyz".to_string()));
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn parse_ws_headers_keeps_extra_colons_in_the_value() {
+        let headers = parse_ws_headers("Authorization: Bearer abc:def");
+        assert_eq!(headers.get("Authorization"), Some(&"Bearer abc:def".to_string()));
+    }
+
+    #[test]
+    fn parse_ws_headers_trims_whitespace_around_name_and_value() {
+        let headers = parse_ws_headers("  x-api-key  :  abc  ");
+        assert_eq!(headers.get("x-api-key"), Some(&"abc".to_string()));
+    }
+
+    #[test]
+    fn parse_ws_headers_drops_entries_with_an_empty_name() {
+        let headers = parse_ws_headers(": abc, x-api-key: def");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get("x-api-key"), Some(&"def".to_string()));
+    }
+
+    #[test]
+    fn backoff_is_bounded_and_never_overflows() {
+        let connector = SolanaConnector::new("ws://localhost", 0, HashMap::new())
+            .with_backoff(Duration::from_millis(500), Duration::from_secs(30));
+        // First attempt is at least the base delay.
+        assert!(connector.backoff_delay(1) >= Duration::from_millis(500));
+        // Large attempts must not panic and stay capped at max_delay (+ jitter).
+        for attempt in [33u32, 40, u32::MAX] {
+            let delay = connector.backoff_delay(attempt);
+            assert!(delay <= Duration::from_secs(30) + Duration::from_millis(500));
+            assert!(delay >= Duration::from_secs(30));
+        }
+    }
+
+    #[tokio::test]
+    async fn confirm_resolves_pending_subscribe_and_registers_server_id() {
+        let subs = SubscriptionManager::new();
+        let (consumer, _rx) = mpsc::channel(1);
+        let client_sub_id = subs
+            .register("programSubscribe".to_string(), json!([]), consumer, None)
+            .await;
+        subs.note_pending(1, client_sub_id).await;
+
+        assert!(subs.confirm(1, 42).await);
+        assert!(subs.consumer_for(42).await.is_some());
+        // A second reply for a request id that was already resolved
+        // shouldn't confirm anything.
+        assert!(!subs.confirm(1, 42).await);
+    }
+
+    #[tokio::test]
+    async fn consumer_for_is_none_until_confirmed() {
+        let subs = SubscriptionManager::new();
+        let (consumer, _rx) = mpsc::channel(1);
+        subs.register("programSubscribe".to_string(), json!([]), consumer, None)
+            .await;
+        assert!(subs.consumer_for(42).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reset_server_ids_clears_routing_and_pending_confirmations() {
+        let subs = SubscriptionManager::new();
+        let (consumer, _rx) = mpsc::channel(1);
+        let client_sub_id = subs
+            .register("programSubscribe".to_string(), json!([]), consumer, None)
+            .await;
+        subs.note_pending(1, client_sub_id).await;
+        assert!(subs.confirm(1, 42).await);
+
+        subs.reset_server_ids().await;
+
+        assert!(subs.consumer_for(42).await.is_none());
+        // The old request id is no longer parked, so a stray late reply can't
+        // confirm a subscription it doesn't belong to.
+        assert!(!subs.confirm(1, 99).await);
+        let snapshot = subs.snapshot().await;
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].server_sub_id, None);
+    }
+
+    #[tokio::test]
+    async fn confirm_fires_the_subscribe_ack() {
+        let subs = SubscriptionManager::new();
+        let (consumer, _rx) = mpsc::channel(1);
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let client_sub_id = subs
+            .register("programSubscribe".to_string(), json!([]), consumer, Some(ack_tx))
+            .await;
+        subs.note_pending(1, client_sub_id).await;
+
+        assert!(subs.confirm(1, 42).await);
+
+        assert_eq!(ack_rx.await.unwrap(), client_sub_id);
+    }
+
+    #[tokio::test]
+    async fn fail_pending_drops_the_subscription_and_its_ack() {
+        let subs = SubscriptionManager::new();
+        let (consumer, _rx) = mpsc::channel(1);
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let client_sub_id = subs
+            .register("programSubscribe".to_string(), json!([]), consumer, Some(ack_tx))
+            .await;
+        subs.note_pending(1, client_sub_id).await;
+
+        let removed = subs.fail_pending(1).await.unwrap();
+        assert_eq!(removed.client_sub_id, client_sub_id);
+
+        // Dropping the ack without firing surfaces the rejection to the caller.
+        assert!(ack_rx.await.is_err());
+        // The dead subscription isn't left around to be replayed on reconnect.
+        assert!(subs.snapshot().await.is_empty());
+        // The request id is no longer parked, so a late duplicate reply is a no-op.
+        assert!(subs.fail_pending(1).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dispatch_frame_completes_pending_call() {
+        let connector = SolanaConnector::new("ws://localhost", 0, HashMap::new());
+        let (tx, rx) = oneshot::channel();
+        connector.pending.lock().await.insert(7, tx);
+        let (sender, _sender_rx) = mpsc::channel::<Value>(1);
+        let (typed, _typed_rx) = mpsc::channel::<ProgramNotification>(1);
+
+        connector
+            .dispatch_frame(json!({"jsonrpc": "2.0", "id": 7, "result": "ok"}), &sender, &typed)
+            .await;
+
+        let reply = rx.await.unwrap();
+        assert_eq!(reply["result"], "ok");
+    }
+
+    #[tokio::test]
+    async fn dispatch_frame_routes_subscribe_confirmation_without_touching_pending_calls() {
+        let connector = SolanaConnector::new("ws://localhost", 0, HashMap::new());
+        let (consumer, mut consumer_rx) = mpsc::channel(1);
+        let client_sub_id = connector
+            .subscriptions()
+            .register("programSubscribe".to_string(), json!([]), consumer, None)
+            .await;
+        connector.subscriptions().note_pending(1, client_sub_id).await;
+        let (call_tx, _call_rx) = oneshot::channel();
+        connector.pending.lock().await.insert(1, call_tx);
+        let (sender, _sender_rx) = mpsc::channel::<Value>(1);
+        let (typed, _typed_rx) = mpsc::channel::<ProgramNotification>(1);
+
+        connector
+            .dispatch_frame(json!({"jsonrpc": "2.0", "id": 1, "result": 42}), &sender, &typed)
+            .await;
+
+        assert!(connector.subscriptions().consumer_for(42).await.is_some());
+        assert!(consumer_rx.try_recv().is_err());
+        // The pending call parked under the same id is left untouched.
+        assert!(connector.pending.lock().await.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn dispatch_frame_drops_subscription_rejected_by_an_error_reply() {
+        let connector = SolanaConnector::new("ws://localhost", 0, HashMap::new());
+        let (consumer, _consumer_rx) = mpsc::channel(1);
+        let client_sub_id = connector
+            .subscriptions()
+            .register("programSubscribe".to_string(), json!([]), consumer, None)
+            .await;
+        connector.subscriptions().note_pending(1, client_sub_id).await;
+        let (call_tx, _call_rx) = oneshot::channel();
+        connector.pending.lock().await.insert(1, call_tx);
+        let (sender, _sender_rx) = mpsc::channel::<Value>(1);
+        let (typed, _typed_rx) = mpsc::channel::<ProgramNotification>(1);
+
+        let error_reply = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": -32602, "message": "invalid program id"},
+        });
+        connector.dispatch_frame(error_reply, &sender, &typed).await;
+
+        // The rejected subscription is gone, not left around with a dangling
+        // `server_sub_id: None` to be replayed on the next resubscribe.
+        assert!(connector.subscriptions().snapshot().await.is_empty());
+        // And it didn't get misreported as an unrouted generic reply either.
+        assert!(connector.pending.lock().await.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn dispatch_frame_routes_notification_to_its_own_consumer() {
+        let connector = SolanaConnector::new("ws://localhost", 0, HashMap::new());
+        let (consumer, mut consumer_rx) = mpsc::channel(1);
+        let client_sub_id = connector
+            .subscriptions()
+            .register("programSubscribe".to_string(), json!([]), consumer, None)
+            .await;
+        connector.subscriptions().note_pending(1, client_sub_id).await;
+        connector.subscriptions().confirm(1, 42).await;
+        let (sender, mut sender_rx) = mpsc::channel::<Value>(1);
+        let (typed, _typed_rx) = mpsc::channel::<ProgramNotification>(1);
+
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "programNotification",
+            "params": {"subscription": 42, "result": {}},
+        });
+        connector.dispatch_frame(notification, &sender, &typed).await;
+
+        assert!(consumer_rx.try_recv().is_ok());
+        // The fallback default-sender should not also receive it.
+        assert!(sender_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn dispatch_frame_broadcasts_notifications_to_every_registered_consumer() {
+        let connector = SolanaConnector::new("ws://localhost", 0, HashMap::new());
+        let mut first = connector.register().await;
+        let mut second = connector.register().await;
+        let (sender, _sender_rx) = mpsc::channel::<Value>(1);
+        let (typed, _typed_rx) = mpsc::channel::<ProgramNotification>(1);
+
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "programNotification",
+            "params": {"subscription": 7, "result": {}},
+        });
+        connector.dispatch_frame(notification, &sender, &typed).await;
+
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+    }
+}